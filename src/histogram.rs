@@ -2,14 +2,18 @@ use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::sync::{Arc, Mutex};
-use std::thread;
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
 fn main() {
     let mut args = env::args().skip(1); // Skip the program name
     let mut output_file = None;
     let mut input_file = None;
     let mut num_threads = 1;
+    let mut show_stats = false;
+    let mut min_count: u32 = 1;
+    let mut machine_readable = false;
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
@@ -23,6 +27,19 @@ fn main() {
                     .parse()
                     .expect("Invalid thread count");
             }
+            "--stats" => {
+                show_stats = true;
+            }
+            "--min-count" => {
+                min_count = args
+                    .next()
+                    .expect("Missing min-count value")
+                    .parse()
+                    .expect("Invalid min-count value");
+            }
+            "--machine-readable" => {
+                machine_readable = true;
+            }
             _ => {
                 input_file = Some(arg);
             }
@@ -38,64 +55,83 @@ fn main() {
 
     if let Some(output_file) = output_file {
         let mut output = File::create(output_file).expect("Failed to create output file");
-        for (count, frequency) in histogram {
+        for (count, frequency) in &histogram {
             writeln!(output, "{}	{}", count, frequency).expect("Failed to write to output file");
         }
     } else {
-        for (count, frequency) in histogram {
+        for (count, frequency) in &histogram {
             println!("{} {}", count, frequency);
         }
     }
-}
 
-fn read_kmer_counts_threaded(file_path: &str, num_threads: usize) -> HashMap<String, u32> {
-    let file = File::open(file_path).expect("Failed to open file");
-    let reader = BufReader::new(file);
+    if show_stats {
+        let stats = compute_spectrum_stats(&histogram, min_count);
+        print_spectrum_stats(&stats, machine_readable);
+    }
+}
 
-    let mut lines = Vec::new();
-    for line in reader.lines() {
-        lines.push(line.expect("Failed to read line"));
+// Merge one count map into another, summing counts for shared keys.
+fn merge_counts(mut into: HashMap<String, u32>, from: HashMap<String, u32>) -> HashMap<String, u32> {
+    for (kmer, count) in from {
+        *into.entry(kmer).or_insert(0) += count;
     }
+    into
+}
 
-    let kmer_counts = Arc::new(Mutex::new(HashMap::new()));
-    let mut handles = Vec::with_capacity(num_threads);
+// Parse a single "<kmer> <count>" line into its key/value pair.
+fn parse_count_line(line: &str) -> (String, u32) {
+    let mut parts = line.split_whitespace();
+    let kmer = parts.next().expect("Invalid line format").to_string();
+    let count: u32 = parts
+        .next()
+        .expect("Invalid line format")
+        .parse()
+        .expect("Invalid count");
+    (kmer, count)
+}
 
-    let chunk_size = (lines.len() + num_threads - 1) / num_threads;
-    let mut start = 0;
+// Number of lines buffered per batch before being handed to the worker
+// pool, bounding memory to a small window of the file rather than the whole
+// input.
+const BATCH_SIZE: usize = 10_000;
 
-    for _ in 0..num_threads {
-        let end = std::cmp::min(start + chunk_size, lines.len());
-        let lines_chunk = lines[start..end].to_vec();
-        let kmer_counts = Arc::clone(&kmer_counts);
+fn count_batch(pool: &rayon::ThreadPool, batch: &[String]) -> HashMap<String, u32> {
+    pool.install(|| {
+        batch
+            .par_iter()
+            .fold(HashMap::new, |mut counts, line| {
+                let (kmer, count) = parse_count_line(line);
+                *counts.entry(kmer).or_insert(0) += count;
+                counts
+            })
+            .reduce(HashMap::new, merge_counts)
+    })
+}
 
-        let handle = thread::spawn(move || {
-            let mut thread_counts = HashMap::new();
+fn read_kmer_counts_threaded(file_path: &str, num_threads: usize) -> HashMap<String, u32> {
+    let file = File::open(file_path).expect("Failed to open file");
+    let reader = BufReader::new(file);
 
-            for line in lines_chunk {
-                let mut parts = line.split_whitespace();
-                let kmer = parts.next().expect("Invalid line format").to_string();
-                let count: u32 = parts.next().expect("Invalid line format").parse().expect("Invalid count");
-                *thread_counts.entry(kmer).or_insert(0) += count;
-            }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .expect("Failed to build thread pool");
 
-            let mut global_counts = kmer_counts.lock().unwrap();
-            for (kmer, count) in thread_counts {
-                *global_counts.entry(kmer).or_insert(0) += count;
-            }
-        });
+    let mut total_counts = HashMap::new();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
 
-        handles.push(handle);
-        start = end;
+    for line in reader.lines() {
+        batch.push(line.expect("Failed to read line"));
+        if batch.len() >= BATCH_SIZE {
+            total_counts = merge_counts(total_counts, count_batch(&pool, &batch));
+            batch.clear();
+        }
     }
-
-    for handle in handles {
-        handle.join().expect("Failed to join thread");
+    if !batch.is_empty() {
+        total_counts = merge_counts(total_counts, count_batch(&pool, &batch));
     }
 
-    Arc::try_unwrap(kmer_counts)
-        .expect("Failed to unwrap Arc")
-        .into_inner()
-        .expect("Failed to acquire mutex")
+    total_counts
 }
 
 fn create_histogram(kmer_counts: &HashMap<String, u32>) -> HashMap<u32, u32> {
@@ -108,3 +144,167 @@ fn create_histogram(kmer_counts: &HashMap<String, u32>) -> HashMap<u32, u32> {
     histogram
 }
 
+// Derived quantities summarizing a k-mer count->frequency spectrum.
+struct SpectrumStats {
+    total_kmer_instances: u64,
+    distinct_kmers: u64,
+    singletons: u64,
+    het_coverage_peak: Option<u32>,
+    hom_coverage_peak: Option<u32>,
+    estimated_genome_size: Option<u64>,
+}
+
+// Find the local maxima of a (count, frequency) histogram already sorted
+// ascending by count, returning the count of each in increasing order.
+fn find_coverage_peaks(histogram: &[(u32, u32)]) -> Vec<u32> {
+    let mut peaks = Vec::new();
+
+    for i in 0..histogram.len() {
+        let (count, frequency) = histogram[i];
+        let prev_frequency = if i == 0 { 0 } else { histogram[i - 1].1 };
+        let next_frequency = histogram.get(i + 1).map_or(0, |&(_, f)| f);
+
+        if frequency > prev_frequency && frequency > next_frequency {
+            peaks.push(count);
+        }
+    }
+
+    peaks
+}
+
+// Summarize a sorted count->frequency spectrum, dropping the low-count
+// error tail below `min_count` before looking for coverage peaks. The
+// homozygous (main) peak is the local maximum with the highest frequency;
+// the heterozygous peak, if any, is the next highest-frequency local
+// maximum. Genome size is estimated as the error-excluded k-mer instance
+// total over the homozygous peak's coverage.
+fn compute_spectrum_stats(histogram: &[(u32, u32)], min_count: u32) -> SpectrumStats {
+    let total_kmer_instances: u64 = histogram
+        .iter()
+        .map(|&(count, frequency)| count as u64 * frequency as u64)
+        .sum();
+    let distinct_kmers: u64 = histogram.iter().map(|&(_, frequency)| frequency as u64).sum();
+    let singletons: u64 = histogram
+        .iter()
+        .find(|&&(count, _)| count == 1)
+        .map_or(0, |&(_, frequency)| frequency as u64);
+
+    let filtered: Vec<(u32, u32)> = histogram
+        .iter()
+        .copied()
+        .filter(|&(count, _)| count >= min_count)
+        .collect();
+    let filtered_kmer_instances: u64 = filtered
+        .iter()
+        .map(|&(count, frequency)| count as u64 * frequency as u64)
+        .sum();
+    let peaks = find_coverage_peaks(&filtered);
+
+    let main_peak = filtered
+        .iter()
+        .max_by_key(|&&(_, frequency)| frequency)
+        .map(|&(count, _)| count);
+    let frequency_of = |count: u32| -> u32 {
+        filtered.iter().find(|&&(c, _)| c == count).map_or(0, |&(_, f)| f)
+    };
+
+    let hom_coverage_peak = main_peak;
+    let het_coverage_peak = peaks
+        .iter()
+        .copied()
+        .filter(|&count| Some(count) != hom_coverage_peak)
+        .max_by_key(|&count| frequency_of(count));
+
+    let estimated_genome_size = hom_coverage_peak
+        .filter(|&peak| peak > 0)
+        .map(|peak| filtered_kmer_instances / peak as u64);
+
+    SpectrumStats {
+        total_kmer_instances,
+        distinct_kmers,
+        singletons,
+        het_coverage_peak,
+        hom_coverage_peak,
+        estimated_genome_size,
+    }
+}
+
+fn print_spectrum_stats(stats: &SpectrumStats, machine_readable: bool) {
+    println!("total_kmer_instances: {}", stats.total_kmer_instances);
+    println!("distinct_kmers: {}", stats.distinct_kmers);
+    println!("singletons: {}", stats.singletons);
+    println!(
+        "heterozygous_coverage_peak: {}",
+        stats.het_coverage_peak.map_or("n/a".to_string(), |p| p.to_string())
+    );
+    println!(
+        "homozygous_coverage_peak: {}",
+        stats.hom_coverage_peak.map_or("n/a".to_string(), |p| p.to_string())
+    );
+    println!(
+        "estimated_genome_size: {}",
+        stats.estimated_genome_size.map_or("n/a".to_string(), |g| g.to_string())
+    );
+
+    if machine_readable {
+        println!(
+            "stats\t{}\t{}\t{}\t{}\t{}\t{}",
+            stats.total_kmer_instances,
+            stats.distinct_kmers,
+            stats.singletons,
+            stats.het_coverage_peak.map_or("NA".to_string(), |p| p.to_string()),
+            stats.hom_coverage_peak.map_or("NA".to_string(), |p| p.to_string()),
+            stats
+                .estimated_genome_size
+                .map_or("NA".to_string(), |g| g.to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_coverage_peaks_finds_local_maxima() {
+        let histogram = [(5, 10), (10, 5), (15, 50), (20, 20), (30, 80), (35, 30)];
+        assert_eq!(find_coverage_peaks(&histogram), vec![5, 15, 30]);
+    }
+
+    #[test]
+    fn compute_spectrum_stats_picks_main_peak_by_frequency() {
+        let histogram = [(1, 1000), (5, 10), (10, 5), (15, 50), (20, 20), (30, 80), (35, 30)];
+        let stats = compute_spectrum_stats(&histogram, 3);
+
+        // The count==1 error spike (frequency 1000) is excluded by min_count
+        // and must not be picked as either peak.
+        assert_eq!(stats.hom_coverage_peak, Some(30));
+        assert_eq!(stats.het_coverage_peak, Some(15));
+    }
+
+    #[test]
+    fn compute_spectrum_stats_excludes_error_tail_from_genome_size_numerator() {
+        let histogram = [(1, 1000), (5, 10), (10, 5), (15, 50), (20, 20), (30, 80), (35, 30)];
+        let stats = compute_spectrum_stats(&histogram, 3);
+
+        // Full total includes the error tail; the genome-size estimate must
+        // use the error-excluded total instead, so it differs from the naive
+        // total_kmer_instances / peak calculation.
+        assert_eq!(stats.total_kmer_instances, 5700);
+        assert_eq!(stats.estimated_genome_size, Some(156));
+        assert_ne!(
+            stats.estimated_genome_size,
+            Some(stats.total_kmer_instances / stats.hom_coverage_peak.unwrap() as u64)
+        );
+    }
+
+    #[test]
+    fn compute_spectrum_stats_reports_singletons_and_distinct_counts() {
+        let histogram = [(1, 100), (2, 40), (3, 10)];
+        let stats = compute_spectrum_stats(&histogram, 1);
+
+        assert_eq!(stats.singletons, 100);
+        assert_eq!(stats.distinct_kmers, 150);
+        assert_eq!(stats.total_kmer_instances, 100 + 2 * 40 + 3 * 10);
+    }
+}
@@ -2,154 +2,213 @@ use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use xxhash_rust::xxh3::xxh3_64;
-
-// Compute the canonical representation of a k-mer
-fn canonical_kmer(kmer: &str) -> String {
-    let rev_comp = revcomp(kmer);
-    if kmer < rev_comp.as_str() {
-        kmer.to_string()
-    } else {
-        rev_comp
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+// Map a DNA base to its 2-bit code (A=0, C=1, G=2, T=3). Returns None for
+// anything outside ACGT (e.g. `N`) so ambiguous windows can be skipped.
+fn base_to_code(base: u8) -> Option<u64> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
     }
 }
 
-// Compute the reverse complement of a DNA sequence
-fn revcomp(sequence: &str) -> String {
-    let mut rev_comp = String::with_capacity(sequence.len());
-    for c in sequence.chars().rev() {
-        rev_comp.push(complement(c));
+// Map a 2-bit code back to its DNA base.
+fn code_to_base(code: u64) -> u8 {
+    match code {
+        0 => b'A',
+        1 => b'C',
+        2 => b'G',
+        3 => b'T',
+        _ => unreachable!(),
     }
-    rev_comp
 }
 
-// Complement a DNA base
-fn complement(base: char) -> char {
-    match base {
-        'A' => 'T',
-        'C' => 'G',
-        'G' => 'C',
-        'T' => 'A',
-        _ => base,
+// Bitmask covering the low 2*k bits used to store a k-mer's code.
+fn kmer_mask(k: usize) -> u64 {
+    if k >= 32 {
+        u64::MAX
+    } else {
+        (1u64 << (2 * k)) - 1
     }
 }
 
-// Hash a k-mer using xxHash
-fn hash_kmer(kmer: &str) -> u64 {
-    xxh3_64(kmer.as_bytes())
+// Reverse the order of the 2-bit groups in a k-mer code.
+fn reverse_kmer_code(mut code: u64, k: usize) -> u64 {
+    let mut reversed = 0u64;
+    for _ in 0..k {
+        reversed = (reversed << 2) | (code & 0b11);
+        code >>= 2;
+    }
+    reversed
 }
 
-// Count canonical k-mers in a DNA sequence
-fn count_canonical_kmers(sequence: &str, k: usize) -> HashMap<u64, u32> {
-    let mut kmer_counts = HashMap::new();
+// Compute the reverse-complement of a k-mer code directly in 2-bit space:
+// complementing a base is XOR with 0b11, and reversing the sequence is
+// reversing the order of the 2-bit groups.
+fn revcomp_kmer_code(code: u64, k: usize) -> u64 {
+    let complemented = !code & kmer_mask(k);
+    reverse_kmer_code(complemented, k)
+}
 
-    for i in 0..(sequence.len() - k + 1) {
-        let kmer = &sequence[i..i+k];
-        let canonical_kmer = canonical_kmer(kmer);
-        let hash = hash_kmer(&canonical_kmer);
-        *kmer_counts.entry(hash).or_insert(0) += 1;
+// Decode a packed k-mer code back into its DNA sequence, extracting two
+// bits per position from the low end and masking to 2*k bits.
+fn decode_kmer(code: u64, k: usize) -> String {
+    let mut code = code & kmer_mask(k);
+    let mut bases = vec![0u8; k];
+    for i in (0..k).rev() {
+        bases[i] = code_to_base(code & 0b11);
+        code >>= 2;
     }
-
-    kmer_counts
+    String::from_utf8(bases).expect("Decoded k-mer is not valid UTF-8")
 }
 
-fn count_kmers_from_file_threaded(
-    file_path: &str,
-    k: usize,
-    num_threads: usize,
-) -> HashMap<u64, u32> {
-    let file = File::open(file_path).expect("Failed to open file");
-    let reader = BufReader::new(file);
-
-    let mut sequences = Vec::new();
-    let mut sequence = String::new();
-    let mut is_fastq = false;
-
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line");
-        if line.starts_with('>') {
-            if !sequence.is_empty() {
-                sequences.push(sequence.clone());
-                sequence.clear();
+// Count canonical k-mers in a DNA sequence using invertible 2-bit packing.
+// Windows containing any non-ACGT base are skipped rather than mapped to A.
+fn count_canonical_kmers(sequence: &str, k: usize) -> HashMap<u64, u32> {
+    let mask = kmer_mask(k);
+    let mut kmer_counts = HashMap::new();
+    let mut code: u64 = 0;
+    let mut valid_run = 0usize;
+
+    for &base in sequence.as_bytes() {
+        match base_to_code(base) {
+            Some(bits) => {
+                code = ((code << 2) | bits) & mask;
+                valid_run += 1;
             }
-        } else if line.starts_with('@') {
-            if !sequence.is_empty() {
-                sequences.push(sequence.clone());
-                sequence.clear();
+            None => {
+                code = 0;
+                valid_run = 0;
+                continue;
             }
-            is_fastq = true;
-        } else if is_fastq && (line.starts_with('+') || line.starts_with('#')) {
-            // Skip quality lines in FASTQ
-        } else {
-            sequence.push_str(&line);
+        }
+
+        if valid_run >= k {
+            let canonical = code.min(revcomp_kmer_code(code, k));
+            *kmer_counts.entry(canonical).or_insert(0) += 1;
         }
     }
 
-    if !sequence.is_empty() {
-        sequences.push(sequence);
+    kmer_counts
+}
+
+// Merge one count map into another, summing counts for shared keys.
+fn merge_counts(mut into: HashMap<u64, u32>, from: HashMap<u64, u32>) -> HashMap<u64, u32> {
+    for (kmer_code, count) in from {
+        *into.entry(kmer_code).or_insert(0) += count;
     }
+    into
+}
+
+// Number of sequences buffered per batch before being handed to the worker
+// pool, bounding memory to a small window of records rather than the whole
+// file.
+const BATCH_SIZE: usize = 10_000;
 
-    let kmer_counts = Arc::new(Mutex::new(HashMap::new()));
-    let mut handles = Vec::with_capacity(num_threads);
+// Streams FASTA/FASTQ records from a reader one sequence at a time. A FASTQ
+// record is exactly four lines (`@header`, sequence, `+`, quality); a FASTA
+// record is a `>header` followed by one or more sequence lines up to the
+// next header.
+struct RecordReader<R: BufRead> {
+    lines: std::iter::Peekable<std::io::Lines<R>>,
+}
 
-    let chunk_size = (sequences.len() + num_threads - 1) / num_threads;
-    let mut start = 0;
+impl<R: BufRead> RecordReader<R> {
+    fn new(reader: R) -> Self {
+        RecordReader {
+            lines: reader.lines().peekable(),
+        }
+    }
+}
 
-    for _ in 0..num_threads {
-        let end = std::cmp::min(start + chunk_size, sequences.len());
-        let sequences_chunk = sequences[start..end].to_vec();
-        let kmer_counts = Arc::clone(&kmer_counts);
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = String;
 
-        let handle = thread::spawn(move || {
-            let mut thread_counts = HashMap::new();
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let line = self.lines.next()?.expect("Failed to read line");
 
-            for sequence in sequences_chunk {
-                let counts = count_canonical_kmers(&sequence, k);
-                for (kmer_hash, count) in counts {
-                    *thread_counts.entry(kmer_hash).or_insert(0) += count;
+            if line.starts_with('@') {
+                let sequence = self
+                    .lines
+                    .next()
+                    .expect("Truncated FASTQ record: missing sequence line")
+                    .expect("Failed to read line");
+                let plus_line = self
+                    .lines
+                    .next()
+                    .expect("Truncated FASTQ record: missing '+' line")
+                    .expect("Failed to read line");
+                assert!(
+                    plus_line.starts_with('+'),
+                    "Malformed FASTQ record: expected '+' line, got: {}",
+                    plus_line
+                );
+                self.lines
+                    .next()
+                    .expect("Truncated FASTQ record: missing quality line")
+                    .expect("Failed to read line");
+                return Some(sequence);
+            } else if line.starts_with('>') {
+                let mut sequence = String::new();
+                while let Some(Ok(next)) = self.lines.peek() {
+                    if next.starts_with('>') || next.starts_with('@') {
+                        break;
+                    }
+                    sequence.push_str(&self.lines.next().unwrap().expect("Failed to read line"));
                 }
+                return Some(sequence);
             }
+            // Blank or stray line outside any record: skip it.
+        }
+    }
+}
 
-            let mut global_counts = kmer_counts.lock().unwrap();
-            for (kmer_hash, count) in thread_counts {
-                *global_counts.entry(kmer_hash).or_insert(0) += count;
-            }
-        });
+fn count_kmers_from_file_threaded(
+    file_path: &str,
+    k: usize,
+    num_threads: usize,
+) -> HashMap<u64, u32> {
+    let file = File::open(file_path).expect("Failed to open file");
+    let records = RecordReader::new(BufReader::new(file));
 
-        handles.push(handle);
-        start = end;
-    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .expect("Failed to build thread pool");
+
+    let mut total_counts = HashMap::new();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
 
-    for handle in handles {
-        handle.join().expect("Failed to join thread");
+    for sequence in records {
+        batch.push(sequence);
+        if batch.len() >= BATCH_SIZE {
+            total_counts = merge_counts(total_counts, count_batch(&pool, &batch, k));
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        total_counts = merge_counts(total_counts, count_batch(&pool, &batch, k));
     }
 
-    Arc::try_unwrap(kmer_counts)
-        .expect("Failed to unwrap Arc")
-        .into_inner()
-        .expect("Failed to acquire mutex")
+    total_counts
 }
 
-// Convert a k-mer hash back to the DNA sequence
-fn hash_to_kmer(hash: u64, k: usize) -> String {
-    let mut kmer = String::with_capacity(k);
-    let mut remaining_hash = hash;
-
-    for _ in 0..k {
-        let base = match remaining_hash & 0x3 {
-            0 => 'A',
-            1 => 'C',
-            2 => 'G',
-            3 => 'T',
-            _ => unreachable!(),
-        };
-        kmer.push(base);
-        remaining_hash >>= 2;
-    }
-
-    kmer
+// Count k-mers across a batch of sequences using the given thread pool,
+// reducing per-sequence maps pairwise instead of serializing through a lock.
+fn count_batch(pool: &rayon::ThreadPool, batch: &[String], k: usize) -> HashMap<u64, u32> {
+    pool.install(|| {
+        batch
+            .par_iter()
+            .map(|sequence| count_canonical_kmers(sequence, k))
+            .reduce(HashMap::new, merge_counts)
+    })
 }
 
 fn main() {
@@ -181,21 +240,76 @@ fn main() {
     }
 
     let k = k.expect("K-mer size not provided");
+    assert!(
+        (1..=32).contains(&k),
+        "k must be between 1 and 32 to fit in a packed u64 code"
+    );
     let input_file = input_file.expect("Input file not provided");
 
     let kmer_counts = count_kmers_from_file_threaded(&input_file, k, num_threads);
 
     if let Some(output_file) = output_file {
         let mut output = File::create(output_file).expect("Failed to create output file");
-        for (kmer_hash, count) in &kmer_counts {
-            let kmer = hash_to_kmer(*kmer_hash, k);
+        for (kmer_code, count) in &kmer_counts {
+            let kmer = decode_kmer(*kmer_code, k);
             writeln!(output, "{}	{}", kmer, count).expect("Failed to write to output file");
         }
     } else {
-        for (kmer_hash, count) in &kmer_counts {
-            let kmer = hash_to_kmer(*kmer_hash, k);
+        for (kmer_code, count) in &kmer_counts {
+            let kmer = decode_kmer(*kmer_code, k);
             println!("{} {}", kmer, count);
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_kmer_round_trips_through_encoding() {
+        let k = 5;
+        let mut code: u64 = 0;
+        for base in "ACGTA".bytes() {
+            code = (code << 2) | base_to_code(base).unwrap();
+        }
+        assert_eq!(decode_kmer(code, k), "ACGTA");
+    }
+
+    #[test]
+    fn revcomp_kmer_code_matches_sequence_reverse_complement() {
+        let k = 4;
+        let mut code: u64 = 0;
+        for base in "AACG".bytes() {
+            code = (code << 2) | base_to_code(base).unwrap();
+        }
+        let revcomp = revcomp_kmer_code(code, k);
+        assert_eq!(decode_kmer(revcomp, k), "CGTT");
+    }
+
+    #[test]
+    fn revcomp_of_revcomp_is_identity() {
+        let k = 6;
+        let mut code: u64 = 0;
+        for base in "GATTAC".bytes() {
+            code = (code << 2) | base_to_code(base).unwrap();
+        }
+        assert_eq!(revcomp_kmer_code(revcomp_kmer_code(code, k), k), code);
+    }
+
+    #[test]
+    fn count_canonical_kmers_picks_canonical_of_each_strand() {
+        // "AAAA" and its revcomp "TTTT" must collapse to the same key.
+        let forward = count_canonical_kmers("AAAA", 4);
+        let revcomp = count_canonical_kmers("TTTT", 4);
+        assert_eq!(forward, revcomp);
+        assert_eq!(forward.values().sum::<u32>(), 1);
+    }
+
+    #[test]
+    fn count_canonical_kmers_skips_windows_with_n() {
+        // The run is broken by 'N', so no complete 4-mer window is ever valid.
+        let counts = count_canonical_kmers("AANAA", 4);
+        assert!(counts.is_empty());
+    }
+}